@@ -1,17 +1,20 @@
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Condvar, Mutex};
+use std::marker::PhantomData;
+use std::sync::Arc;
 use std::usize;
 
+use registry::{Registry, WorkerThread};
 use sleep::Sleep;
+use sync::atomic::{AtomicUsize, Ordering};
+use sync::{Condvar, Mutex};
 
 /// We define various kinds of latches, which are all a primitive signaling
 /// mechanism. A latch starts as false. Eventually someone calls `set()` and
 /// it becomes true. You can test if it has been set by calling `probe()`.
 ///
 /// Some kinds of latches, but not all, support a `wait()` operation
-/// that will wait until the latch is set, blocking efficiently. That
-/// is not part of the trait since it is not possibly to do with all
-/// latches.
+/// that will wait until the latch is set, blocking efficiently. Those
+/// latches implement the separate `LatchWaitProbe` trait below, since
+/// not every latch can support it.
 ///
 /// The intention is that `set()` is called once, but `probe()` may be
 /// called any number of times. Once `probe()` returns true, the memory
@@ -40,33 +43,80 @@ pub(super) trait LatchProbe {
     fn probe(&self) -> bool;
 }
 
+/// Implemented by latches that support blocking until they are set,
+/// letting generic code (e.g. the scope/injection machinery) be generic
+/// over "a latch I can block on" rather than hard-coding `LockLatch`.
+pub(super) trait LatchWaitProbe: LatchProbe {
+    /// Block the current thread until the latch is set.
+    fn wait(&self);
+}
+
 /// Spin latches are the simplest, most efficient kind, but they do
-/// not support a `wait()` operation. They just have a boolean flag
-/// that becomes true when `set()` is called.
-pub(super) struct SpinLatch {
-    b: AtomicBool,
+/// not support a `wait()` operation. They embed a `CoreLatch` rather than
+/// a bare boolean, so that `set()` can tell -- via the `get_sleepy()` /
+/// `fall_asleep()` protocol -- whether the owning worker was actually
+/// asleep before paying for a wakeup. They also track the `WorkerThread`
+/// that created them, so that for a latch made with `cross()`, when a
+/// wakeup *is* needed, `set()` notifies that worker's registry directly
+/// instead of the caller having to wrap the latch in a `TickleLatch`.
+pub(super) struct SpinLatch<'r> {
+    core_latch: CoreLatch,
+    registry: &'r Arc<Registry>,
+    target_worker_index: usize,
+    cross: bool,
 }
 
-impl SpinLatch {
+impl<'r> SpinLatch<'r> {
+    /// Creates a new spin latch that is owned by `owner`, the worker
+    /// thread on which it was created. The latch is assumed to only ever
+    /// be set by a job running within `owner`'s own pool, so `set()` only
+    /// marks the latch as set and never notifies the registry; only a
+    /// latch made with `cross()` does that.
+    #[inline]
+    pub(super) fn new(owner: &'r WorkerThread) -> SpinLatch<'r> {
+        SpinLatch {
+            core_latch: CoreLatch::new(),
+            registry: owner.registry(),
+            target_worker_index: owner.index(),
+            cross: false,
+        }
+    }
+
+    /// Creates a new spin latch for a job that may be stolen into a
+    /// *different* thread pool than `owner`'s. `set()` will then notify
+    /// `owner`'s registry directly -- but only if `owner` was actually
+    /// sleeping on it -- so the source pool, which may be blocked in
+    /// `join()` waiting on this very latch, resumes promptly.
     #[inline]
-    pub(super) fn new() -> SpinLatch {
+    pub(super) fn cross(owner: &'r WorkerThread) -> SpinLatch<'r> {
         SpinLatch {
-            b: AtomicBool::new(false),
+            cross: true,
+            ..SpinLatch::new(owner)
         }
     }
 }
 
-impl LatchProbe for SpinLatch {
+impl<'r> AsCoreLatch for SpinLatch<'r> {
+    #[inline]
+    fn as_core_latch(&self) -> &CoreLatch {
+        &self.core_latch
+    }
+}
+
+impl<'r> LatchProbe for SpinLatch<'r> {
     #[inline]
     fn probe(&self) -> bool {
-        self.b.load(Ordering::SeqCst)
+        self.as_core_latch().probe()
     }
 }
 
-impl Latch for SpinLatch {
+impl<'r> Latch for SpinLatch<'r> {
     #[inline]
     fn set(&self) {
-        self.b.store(true, Ordering::SeqCst);
+        let wake = self.core_latch.set();
+        if self.cross && wake {
+            self.registry.notify_worker_latch_is_set(self.target_worker_index);
+        }
     }
 }
 
@@ -122,6 +172,13 @@ impl Latch for LockLatch {
     }
 }
 
+impl LatchWaitProbe for LockLatch {
+    #[inline]
+    fn wait(&self) {
+        LockLatch::wait(self)
+    }
+}
+
 /// Counting latches are used to implement scopes. They track a
 /// counter. Unlike other latches, calling `set()` does not
 /// necessarily make the latch be considered `set()`; instead, it just
@@ -163,6 +220,133 @@ impl Latch for CountLatch {
     }
 }
 
+/// Core latch state shared by `SpinLatch` and `OnceLatch`. The state is encoded as a
+/// tiny state machine in a single `AtomicUsize` so that `set()` can tell
+/// whether anyone was actually sleeping on the latch, and therefore whether
+/// a real wakeup (condvar notify, futex wake, etc) is required. This is the
+/// foundation for replacing `TickleLatch`'s broadcast-everyone approach,
+/// which wakes the whole pool on every `set()` regardless of whether
+/// anyone is waiting.
+///
+/// The four states are:
+///
+/// - `UNSET`: the latch has not been set, and no one is sleeping on it.
+/// - `SLEEPY`: a worker is about to go to sleep on this latch, via
+///   `get_sleepy()`, but has not yet committed via `fall_asleep()`.
+/// - `SLEEPING`: a worker has committed to sleeping on this latch.
+/// - `SET`: the latch has been set.
+///
+/// `get_sleepy()` and `fall_asleep()` are two separate steps so that the
+/// worker can check for work one more time in between them; if `set()`
+/// races in during that window, `fall_asleep()`'s CAS will fail and the
+/// worker knows to stay awake instead of sleeping through a wakeup it
+/// already missed.
+const UNSET: usize = 0;
+const SLEEPY: usize = 1;
+const SLEEPING: usize = 2;
+const SET: usize = 3;
+
+pub(super) struct CoreLatch {
+    state: AtomicUsize,
+}
+
+impl CoreLatch {
+    #[inline]
+    pub(super) fn new() -> CoreLatch {
+        CoreLatch {
+            state: AtomicUsize::new(UNSET),
+        }
+    }
+
+    /// Invoked by a worker that is about to go to sleep; attempts the
+    /// `UNSET -> SLEEPY` transition. Returns `true` on success.
+    #[inline]
+    pub(super) fn get_sleepy(&self) -> bool {
+        self.state
+            .compare_exchange(UNSET, SLEEPY, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Invoked by a worker that called `get_sleepy()`, found no work, and
+    /// is now ready to actually sleep. Attempts the `SLEEPY -> SLEEPING`
+    /// transition. Returns `false` if the latch moved out from under it
+    /// (i.e. `set()` raced in), in which case the worker must stay awake.
+    #[inline]
+    pub(super) fn fall_asleep(&self) -> bool {
+        self.state
+            .compare_exchange(SLEEPY, SLEEPING, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Sets the latch. Returns `true` if the previous state was
+    /// `SLEEPING`, meaning some worker is actually asleep and the caller
+    /// must follow up with a real wakeup notification; otherwise no one
+    /// was waiting and nothing further needs to happen.
+    #[inline]
+    pub(super) fn set(&self) -> bool {
+        self.state.swap(SET, Ordering::SeqCst) == SLEEPING
+    }
+
+    /// Test whether the latch is set. Uses `SeqCst` to preserve the
+    /// tickle-then-get-sleepy ordering guarantee described in the sleep
+    /// README.
+    #[inline]
+    pub(super) fn probe(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == SET
+    }
+}
+
+/// Implemented by latches that embed a `CoreLatch` and want to delegate
+/// `probe()`/`set()` to it rather than reimplementing the state machine.
+pub(super) trait AsCoreLatch {
+    fn as_core_latch(&self) -> &CoreLatch;
+}
+
+/// A `OnceLatch` is for the case where a single, specific worker needs to
+/// be woken up, rather than a whole sleeping pool -- e.g. an injected or
+/// terminate job that some particular stalled worker is waiting on. It
+/// wraps a `CoreLatch`, so `set_and_tickle_one()` is naturally idempotent:
+/// a second call just swaps `SET` to `SET` again, finds no one sleeping
+/// and wakes no one, rather than underflowing a counter the way a second
+/// `set()` on a `CountLatch` would. That makes `OnceLatch` safe to use on job
+/// cleanup paths that may fire more than once.
+pub(super) struct OnceLatch {
+    core_latch: CoreLatch,
+}
+
+impl OnceLatch {
+    #[inline]
+    pub(super) fn new() -> OnceLatch {
+        OnceLatch {
+            core_latch: CoreLatch::new(),
+        }
+    }
+
+    /// Set the latch, and, if some worker was actually sleeping on it,
+    /// wake up exactly the worker at `target_worker_index` in `registry`,
+    /// rather than broadcasting to the whole registry.
+    #[inline]
+    pub(super) fn set_and_tickle_one(&self, registry: &Registry, target_worker_index: usize) {
+        if self.core_latch.set() {
+            registry.notify_worker_latch_is_set(target_worker_index);
+        }
+    }
+}
+
+impl AsCoreLatch for OnceLatch {
+    #[inline]
+    fn as_core_latch(&self) -> &CoreLatch {
+        &self.core_latch
+    }
+}
+
+impl LatchProbe for OnceLatch {
+    #[inline]
+    fn probe(&self) -> bool {
+        self.as_core_latch().probe()
+    }
+}
+
 /// A tickling latch wraps another latch type, and will also awaken a thread
 /// pool when it is set.  This is useful for jobs injected between thread pools,
 /// so the source pool can continue processing its own work while waiting.
@@ -213,3 +397,134 @@ where
         L::set(self);
     }
 }
+
+/// As with `&'a L` above, but for an owned `Arc<L>` handle. This lets a
+/// latch be shared by reference *or* by owned `Arc`, which matters once a
+/// job can outlive the stack frame that created it.
+impl<L: Latch> Latch for Arc<L> {
+    #[inline]
+    fn set(&self) {
+        L::set(self);
+    }
+}
+
+impl<L: LatchProbe> LatchProbe for Arc<L> {
+    #[inline]
+    fn probe(&self) -> bool {
+        L::probe(self)
+    }
+}
+
+impl<L: LatchWaitProbe> LatchWaitProbe for Arc<L> {
+    #[inline]
+    fn wait(&self) {
+        L::wait(self)
+    }
+}
+
+/// A type-erased, non-owning reference to a latch. Job types like
+/// `StackJob` only need to call `probe()`/`set()` on whatever latch they
+/// were handed, but the `&'a L` blanket impl above forces them to be
+/// generic over `L`, which means a fresh monomorphization of the job type
+/// per latch kind (`SpinLatch`, `LockLatch`, `CountLatch`, ...).
+/// `LatchRef` collapses all of those into one concrete type by storing a
+/// trait object pointer instead of a generic reference. `OnceLatch` isn't
+/// one of the kinds a `LatchRef` can point at: it doesn't implement
+/// `Latch` at all, since waking a specific worker needs a `&Registry` and
+/// a target index that `Latch::set(&self)` has no room for; jobs backed by
+/// a `OnceLatch` call `set_and_tickle_one` directly instead.
+pub(super) struct LatchRef<'a> {
+    inner: *const (dyn Latch + Sync + 'a),
+    marker: PhantomData<&'a ()>,
+}
+
+impl<'a> LatchRef<'a> {
+    /// Erases the concrete type of `latch`. Unsafe because nothing here
+    /// extends `latch`'s lifetime: the caller must ensure the referent
+    /// outlives the `'a` on the returned `LatchRef`.
+    #[inline]
+    pub(super) unsafe fn new(latch: &'a (dyn Latch + Sync + 'a)) -> Self {
+        LatchRef {
+            inner: latch,
+            marker: PhantomData,
+        }
+    }
+}
+
+// SAFETY: `LatchRef` only ever points at a `dyn Latch + Sync`, and the
+// latch kinds it's constructed from (`SpinLatch`, `LockLatch`,
+// `CountLatch`) are all designed to be set from one thread and probed
+// from another, the same way the `&'a L where L: Latch` blanket impls
+// above already rely on `L: Sync` for concurrent access.
+unsafe impl<'a> Send for LatchRef<'a> {}
+unsafe impl<'a> Sync for LatchRef<'a> {}
+
+impl<'a> LatchProbe for LatchRef<'a> {
+    #[inline]
+    fn probe(&self) -> bool {
+        unsafe { (*self.inner).probe() }
+    }
+}
+
+impl<'a> Latch for LatchRef<'a> {
+    #[inline]
+    fn set(&self) {
+        unsafe { (*self.inner).set() }
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::{CoreLatch, Latch, LatchProbe, LockLatch};
+    use loom::sync::Arc;
+    use loom::thread;
+    use sync::atomic::{AtomicUsize, Ordering};
+
+    // A producer writes `data` and then calls `set()`; a consumer spins on
+    // `probe()` (or blocks in `wait()`) until it sees the latch set, then
+    // reads `data`. Loom explores every interleaving of the two threads and
+    // checks that the consumer always observes the producer's write --
+    // i.e. that `set()` really does synchronize-with `probe()`.
+
+    #[test]
+    fn core_latch_set_happens_before_probe() {
+        loom::model(|| {
+            let data = Arc::new(AtomicUsize::new(0));
+            let latch = Arc::new(CoreLatch::new());
+
+            let data2 = data.clone();
+            let latch2 = latch.clone();
+            let producer = thread::spawn(move || {
+                data2.store(42, Ordering::Relaxed);
+                latch2.set();
+            });
+
+            while !latch.probe() {
+                thread::yield_now();
+            }
+            assert_eq!(data.load(Ordering::Relaxed), 42);
+
+            producer.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn lock_latch_set_happens_before_wait() {
+        loom::model(|| {
+            let data = Arc::new(AtomicUsize::new(0));
+            let latch = Arc::new(LockLatch::new());
+
+            let data2 = data.clone();
+            let latch2 = latch.clone();
+            let producer = thread::spawn(move || {
+                data2.store(42, Ordering::Relaxed);
+                latch2.set();
+            });
+
+            latch.wait();
+            assert_eq!(data.load(Ordering::Relaxed), 42);
+
+            producer.join().unwrap();
+        });
+    }
+}
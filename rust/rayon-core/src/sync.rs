@@ -0,0 +1,24 @@
+//! The latches in `latch.rs` are exactly the kind of seq-cst-sensitive code
+//! that's easy to get subtly wrong -- see the tickle-then-get-sleepy
+//! ordering hazard called out there. To let that code be checked with
+//! [loom](https://github.com/tokio-rs/loom), every `Mutex`, `Condvar`, and
+//! atomic type it uses goes through this module instead of `std::sync`
+//! directly: a normal build re-exports the `std` types, while a build with
+//! `--cfg loom` swaps in loom's drop-in replacements, which explore every
+//! thread interleaving instead of running the code for real.
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::{Condvar, Mutex};
+
+#[cfg(not(loom))]
+pub(crate) mod atomic {
+    pub(crate) use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+}
+
+#[cfg(loom)]
+pub(crate) use loom::sync::{Condvar, Mutex};
+
+#[cfg(loom)]
+pub(crate) mod atomic {
+    pub(crate) use loom::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+}